@@ -2,12 +2,69 @@ use image::{imageops::FilterType, ImageFormat};
 use serde::Deserialize;
 use std::{io::Cursor, sync::Arc};
 
-/// Currently only webp images are being served. Default quality is webp quality is 85.
+/// Images can be re-encoded to webp, avif or jxl. Default quality for all of them is 85.
 #[derive(Clone, Debug)]
 pub struct ImageOptimizer {
     dir: std::path::PathBuf,
-    // The key is Resize::to_string + image name.
-    cache: Arc<dashmap::DashMap<String, Vec<u8>>>,
+    origin: Option<Origin>,
+    watermark: Option<Watermark>,
+    upload: UploadConfig,
+    // The key is Resize::to_string + the negotiated format (if any) + image name, see `key`.
+    cache: Arc<Cache>,
+}
+
+/// Default in-memory cache budget: 256 MiB.
+const DEFAULT_CACHE_MAX_BYTES: usize = 256 * 1024 * 1024;
+
+/// A remote base URL images are fetched from on a local cache miss, see
+/// [`ImageOptimizer::with_origin`].
+#[derive(Clone, Debug)]
+struct Origin {
+    base_url: reqwest::Url,
+    client: reqwest::Client,
+}
+
+/// Corner (or center) a watermark is anchored to, see [`ImageOptimizer::with_watermark`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Gravity {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    Center,
+}
+
+/// A watermark overlay configured via [`ImageOptimizer::with_watermark`],
+/// holding the already-decoded overlay image so it isn't re-decoded per
+/// request.
+#[derive(Clone, Debug)]
+struct Watermark {
+    image: Arc<image::DynamicImage>,
+    gravity: Gravity,
+    opacity: f32,
+    margin: u32,
+}
+
+/// Limits enforced by [`ImageOptimizer::store_image`], see
+/// [`ImageOptimizer::with_upload_config`].
+#[derive(Clone, Debug)]
+struct UploadConfig {
+    max_bytes: usize,
+    allowed_formats: Vec<image::ImageFormat>,
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: 20 * 1024 * 1024,
+            allowed_formats: vec![
+                image::ImageFormat::Jpeg,
+                image::ImageFormat::Png,
+                image::ImageFormat::Gif,
+                image::ImageFormat::WebP,
+            ],
+        }
+    }
 }
 
 impl ImageOptimizer {
@@ -19,10 +76,160 @@ impl ImageOptimizer {
 
         Ok(Self {
             dir,
-            cache: Arc::new(dashmap::DashMap::new()),
+            origin: None,
+            watermark: None,
+            upload: UploadConfig::default(),
+            cache: Arc::new(Cache::new(DEFAULT_CACHE_MAX_BYTES, None)),
         })
     }
 
+    /// Falls back to fetching images from `origin` on a local miss, like a
+    /// CDN front-end proxying an upstream. Successful fetches are written
+    /// back into `dir` so repeat requests are served from disk.
+    pub fn with_origin(mut self, origin: reqwest::Url) -> Self {
+        self.origin = Some(Origin {
+            base_url: origin,
+            client: reqwest::Client::new(),
+        });
+        self
+    }
+
+    /// Configures the optimized-variant cache: an in-memory LRU bounded to
+    /// `max_bytes`, and, if `cache_dir` is set, a persistent disk tier under
+    /// it that survives restarts and is checked before re-encoding.
+    pub fn with_cache_config<P: AsRef<std::path::Path>>(
+        mut self,
+        max_bytes: usize,
+        cache_dir: Option<P>,
+    ) -> Self {
+        self.cache = Arc::new(Cache::new(
+            max_bytes,
+            cache_dir.map(|dir| dir.as_ref().to_owned()),
+        ));
+        self
+    }
+
+    /// Overlays every served image with the watermark loaded from `path`
+    /// (e.g. a logo or copyright notice), positioned at `gravity` with
+    /// `margin` px of padding, scaled relative to the served image's width
+    /// and faded by `opacity`. The decoded overlay is kept
+    /// around so it isn't re-decoded per request. Individual requests can
+    /// opt out with `?watermark=false`.
+    pub fn with_watermark<P: AsRef<std::path::Path>>(
+        mut self,
+        path: P,
+        gravity: Gravity,
+        opacity: f32,
+        margin: u32,
+    ) -> Result<Self, std::io::Error> {
+        let image = image::open(path.as_ref())
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+
+        self.watermark = Some(Watermark {
+            image: Arc::new(image),
+            gravity,
+            opacity,
+            margin,
+        });
+        Ok(self)
+    }
+
+    /// Configures the limits [`Self::store_image`] enforces: the maximum
+    /// accepted upload size and the set of image formats it will store.
+    /// Defaults to 20 MiB and jpeg/png/gif/webp.
+    pub fn with_upload_config(mut self, max_bytes: usize, allowed_formats: Vec<ImageFormat>) -> Self {
+        self.upload = UploadConfig {
+            max_bytes,
+            allowed_formats,
+        };
+        self
+    }
+
+    /// Verifies `bytes` decode as one of the allowed image formats and
+    /// stores them under `dir` using a content-addressed name: the lowercase
+    /// hex SHA-256 of the bytes plus the detected extension. Returns that
+    /// name, which can be fetched (and resized/re-encoded) like any other
+    /// image served from `dir`.
+    pub fn store_image(&self, bytes: Vec<u8>) -> Result<String, UploadError> {
+        if bytes.len() > self.upload.max_bytes {
+            return Err(UploadError::TooLarge);
+        }
+
+        let format = image::guess_format(&bytes).map_err(|_| UploadError::InvalidImage)?;
+        if !self.upload.allowed_formats.contains(&format) {
+            return Err(UploadError::UnsupportedFormat);
+        }
+
+        // Guard against files that merely start with a valid magic number.
+        image::load_from_memory_with_format(&bytes, format)
+            .map_err(|_| UploadError::InvalidImage)?;
+
+        let extension = format.extensions_str().first().unwrap_or(&"bin");
+        let filename = format!("{}.{extension}", sha256_hex(&bytes));
+
+        std::fs::write(self.dir.join(&filename), &bytes).map_err(|_| UploadError::Io)?;
+        self.cache
+            .insert(key(&filename, &Resize::default(), None), bytes);
+
+        Ok(filename)
+    }
+
+    #[cfg(feature = "axum")]
+    pub fn axum_upload_router(self) -> axum::Router {
+        use axum::{
+            body::Bytes, extract::DefaultBodyLimit, response::IntoResponse, routing::post, Router,
+        };
+
+        let max_bytes = self.upload.max_bytes;
+
+        let f = move |bytes: Bytes| {
+            let image_server = self.clone();
+            async move {
+                tokio::task::spawn_blocking(move || image_server.store_image(bytes.to_vec()))
+                    .await
+                    .map_err(|_| UploadError::Io)
+                    .and_then(|result| result)
+                    .into_response()
+            }
+        };
+
+        // `store_image`'s own size check only runs once the body is fully
+        // buffered; cap it at the transport level too so an oversized upload
+        // is rejected before `max_bytes` worth of memory is even allocated.
+        Router::new()
+            .route("/", post(f))
+            .layer(DefaultBodyLimit::max(max_bytes))
+    }
+
+    #[cfg(feature = "poem")]
+    pub fn poem_upload_router(self) -> poem::Route {
+        use poem::{
+            handler, http::StatusCode, middleware::SizeLimit, post, web::Data, EndpointExt,
+            Result, Route,
+        };
+
+        #[handler]
+        async fn upload_image(
+            image_optimizer: Data<&ImageOptimizer>,
+            body: Vec<u8>,
+        ) -> Result<String> {
+            let image_optimizer = image_optimizer.clone();
+            tokio::task::spawn_blocking(move || image_optimizer.store_image(body))
+                .await
+                .map_err(|_| {
+                    poem::Error::from_string("internal error", StatusCode::INTERNAL_SERVER_ERROR)
+                })?
+                .map_err(|err| poem::Error::from_string(err.to_string(), err.status_code()))
+        }
+
+        // See the axum router: wire the same configured limit into the
+        // transport-level size check, not just `store_image`'s in-handler one.
+        let max_bytes = self.upload.max_bytes;
+        Route::new()
+            .at("/", post(upload_image).data(self))
+            .with(SizeLimit::new(max_bytes))
+    }
+
     #[cfg(feature = "axum")]
     pub fn axum_router(self) -> axum::Router {
         use axum::{
@@ -33,17 +240,24 @@ impl ImageOptimizer {
             Router,
         };
 
-        let f = |Path(image): Path<String>, Query(resize): Query<Resize>| async move {
+        let f = |request_headers: HeaderMap,
+                 Path(image): Path<String>,
+                 Query(resize): Query<Resize>| async move {
             let image_server = self;
 
             tracing::debug!("image {image} requested");
 
+            let accept = request_headers
+                .get(header::ACCEPT)
+                .and_then(|v| v.to_str().ok());
+
             let mut headers = HeaderMap::new();
-            let content_type = parse_content_type(&resize, &image);
+            let content_type = parse_content_type(&resize, &image, accept);
             headers.insert(header::CONTENT_TYPE, content_type.parse().unwrap());
             headers.insert(header::CACHE_CONTROL, Self::CACHE_CONTROL.parse().unwrap());
+            headers.insert(header::VARY, "Accept".parse().unwrap());
 
-            (headers, image_server.get_image(&image, &resize))
+            (headers, image_server.get_image(&image, &resize, accept).await)
         };
 
         Router::new().route("/:image", get(f))
@@ -59,25 +273,32 @@ impl ImageOptimizer {
         };
 
         #[handler]
-        fn get_image(
+        async fn get_image(
             image_optimizer: Data<&ImageOptimizer>,
             resize: Query<Resize>,
             path: Path<String>,
+            req: &poem::Request,
         ) -> Result<Response> {
             let image = path.0;
             let resize = *resize;
-            let content_type = parse_content_type(&resize, &image);
+            let accept = req.header("Accept");
+            let content_type = parse_content_type(&resize, &image, accept);
 
             tracing::debug!("image {image} requested");
 
             let bytes = image_optimizer
-                .get_image(&image, &resize)
-                .map_err(|_| http::StatusCode::NOT_FOUND)?;
+                .get_image(&image, &resize, accept)
+                .await
+                .map_err(|err| match err {
+                    GetImageError::NotFound => http::StatusCode::NOT_FOUND,
+                    GetImageError::Upstream => http::StatusCode::BAD_GATEWAY,
+                })?;
 
             Ok(Response::builder()
                 .status(StatusCode::OK)
                 .header("Content-Type", content_type)
                 .header("Cache-Control", ImageOptimizer::CACHE_CONTROL)
+                .header("Vary", "Accept")
                 .body(bytes))
         }
 
@@ -86,67 +307,460 @@ impl ImageOptimizer {
         router
     }
 
-    pub fn get_image(&self, image: &str, resize: &Resize) -> Result<Vec<u8>, ImageNotFound> {
-        if let Some(bytes) = self.cache.get(&key(image, resize)) {
-            return Ok(bytes.to_owned());
-        } else {
-            // Todo: Read with tokio instead of blocking
-            // Todo: Handle error better than just ImageNotFound
-            let mut im = image::open(self.dir.join(image)).map_err(|_| ImageNotFound)?;
-
-            if resize.width.is_some() || resize.height.is_some() {
-                im = im.resize(
-                    resize.width.unwrap_or(u16::MAX) as u32,
-                    resize.height.unwrap_or(u16::MAX) as u32,
-                    FilterType::Lanczos3,
-                );
-            }
+    /// `accept` is the raw value of the request's `Accept` header, used to
+    /// negotiate a format when `resize` doesn't pin one down (see
+    /// [`resolve_format`]). Two requests for the same `image`/`resize` but
+    /// with different `accept` values may resolve to different formats and
+    /// are cached separately.
+    pub async fn get_image(
+        &self,
+        image: &str,
+        resize: &Resize,
+        accept: Option<&str>,
+    ) -> Result<Vec<u8>, GetImageError> {
+        let format = resolve_format(resize, accept);
+        let cache_key = key(image, resize, format);
 
-            if resize.cx.is_some()
-                || resize.cy.is_some()
-                || resize.cwidth.is_some()
-                || resize.cheight.is_some()
-            {
-                im = im.crop_imm(
-                    resize.cx.unwrap_or(0) as u32,
-                    resize.cy.unwrap_or(0) as u32,
-                    resize.cwidth.unwrap_or(u16::MAX) as u32,
-                    resize.cheight.unwrap_or(u16::MAX) as u32,
-                );
-            }
+        let cache = self.cache.clone();
+        let cached = {
+            let cache_key = cache_key.clone();
+            tokio::task::spawn_blocking(move || cache.get(&cache_key))
+                .await
+                .map_err(|_| GetImageError::NotFound)?
+        };
+        if let Some(bytes) = cached {
+            return Ok(bytes);
+        }
+
+        let source = self.load_source(image).await?;
 
-            if resize.webp.unwrap_or(false) {
-                // Todo: Consider other formats, like avif
-                // Todo: Handle error better
-                let im = webp::Encoder::from_image(&im)
-                    .map_err(|_| ImageNotFound)?
-                    .encode(resize.quality.unwrap_or(85) as f32);
-
-                self.cache.insert(key(image, resize), im.to_owned());
-                Ok(im.to_owned())
-            } else {
-                let mut v = Cursor::new(Vec::new());
-                let format = match image.split('.').last().unwrap_or("jpg") {
-                    "jpg" => ImageFormat::Jpeg,
-                    "png" => ImageFormat::Png,
-                    "gif" => ImageFormat::Gif,
-                    _ => ImageFormat::Jpeg,
+        let watermark = match resize.watermark {
+            Some(false) => None,
+            _ => self.watermark.clone(),
+        };
+
+        let resize = *resize;
+        let image = image.to_owned();
+        let bytes = tokio::task::spawn_blocking(move || {
+            encode(&source, &image, &resize, format, watermark.as_ref())
+        })
+        .await
+        .map_err(|_| GetImageError::NotFound)??;
+
+        let cache = self.cache.clone();
+        let insert_bytes = bytes.clone();
+        tokio::task::spawn_blocking(move || cache.insert(cache_key, insert_bytes))
+            .await
+            .map_err(|_| GetImageError::NotFound)?;
+
+        Ok(bytes)
+    }
+
+    /// Reads `image`'s raw bytes from `dir`, falling back to fetching it
+    /// from `origin` (if configured) on a local miss.
+    async fn load_source(&self, image: &str) -> Result<Vec<u8>, GetImageError> {
+        if !is_safe_relative_path(image) {
+            return Err(GetImageError::NotFound);
+        }
+
+        match read_file(&self.dir.join(image)).await {
+            Ok(bytes) => Ok(bytes),
+            Err(_) => {
+                let Some(origin) = &self.origin else {
+                    return Err(GetImageError::NotFound);
                 };
-                im.write_to(&mut v, format).map_err(|_| ImageNotFound)?;
-                self.cache
-                    .insert(key(image, resize), v.get_ref().to_owned());
-                Ok(v.into_inner())
+
+                let url = origin
+                    .base_url
+                    .join(image)
+                    .map_err(|_| GetImageError::Upstream)?;
+
+                // `Url::join` treats an `image` that itself parses as an absolute
+                // URL (e.g. `http://evil.example/internal`) as a full replacement
+                // rather than a path segment, which would let a client redirect
+                // the origin fetch anywhere. Pin the result to the configured origin.
+                if url.origin() != origin.base_url.origin() {
+                    return Err(GetImageError::Upstream);
+                }
+
+                let response = origin
+                    .client
+                    .get(url)
+                    .send()
+                    .await
+                    .map_err(|_| GetImageError::Upstream)?;
+
+                // A clean 404 means the image doesn't exist anywhere, which is
+                // distinct from the origin being unreachable or erroring.
+                if response.status() == reqwest::StatusCode::NOT_FOUND {
+                    return Err(GetImageError::NotFound);
+                }
+                let response = response
+                    .error_for_status()
+                    .map_err(|_| GetImageError::Upstream)?;
+
+                let is_image = response
+                    .headers()
+                    .get(reqwest::header::CONTENT_TYPE)
+                    .and_then(|value| value.to_str().ok())
+                    .is_some_and(|content_type| content_type.starts_with("image/"));
+                if !is_image {
+                    return Err(GetImageError::Upstream);
+                }
+
+                let bytes = response
+                    .bytes()
+                    .await
+                    .map_err(|_| GetImageError::Upstream)?
+                    .to_vec();
+
+                // Best-effort: seed the local dir so repeat requests hit disk.
+                let _ = tokio::fs::write(self.dir.join(image), &bytes).await;
+
+                Ok(bytes)
             }
         }
     }
 }
 
-fn parse_content_type(resize: &Resize, image: &str) -> String {
-    if resize.webp.unwrap_or(false) {
-        "image/webp".into()
+/// A two-level cache for encoded image variants: an in-memory LRU bounded
+/// to a byte budget, backed optionally by a persistent disk tier so variants
+/// survive restarts without needing to be re-encoded.
+#[derive(Debug)]
+struct Cache {
+    max_bytes: usize,
+    current_bytes: std::sync::atomic::AtomicUsize,
+    lru: std::sync::Mutex<lru::LruCache<String, Vec<u8>>>,
+    dir: Option<std::path::PathBuf>,
+}
+
+impl Cache {
+    fn new(max_bytes: usize, dir: Option<std::path::PathBuf>) -> Self {
+        Self {
+            max_bytes,
+            current_bytes: std::sync::atomic::AtomicUsize::new(0),
+            lru: std::sync::Mutex::new(lru::LruCache::unbounded()),
+            dir,
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<Vec<u8>> {
+        if let Some(bytes) = self.lru.lock().unwrap().get(key) {
+            return Some(bytes.clone());
+        }
+
+        let dir = self.dir.as_ref()?;
+        let bytes = std::fs::read(dir.join(disk_key(key))).ok()?;
+        self.insert_memory(key.to_owned(), bytes.clone());
+        Some(bytes)
+    }
+
+    fn insert(&self, key: String, bytes: Vec<u8>) {
+        if let Some(dir) = &self.dir {
+            let _ = std::fs::create_dir_all(dir);
+
+            // Write to a temp file and rename into place so a concurrent
+            // `get` reading the same disk key never observes a torn write.
+            let tmp_path = dir.join(format!("{}.tmp-{}", disk_key(&key), next_tmp_suffix()));
+            if std::fs::write(&tmp_path, &bytes).is_ok() {
+                let _ = std::fs::rename(&tmp_path, dir.join(disk_key(&key)));
+            }
+
+            evict_disk_tier(dir, self.max_bytes);
+        }
+        self.insert_memory(key, bytes);
+    }
+
+    fn insert_memory(&self, key: String, bytes: Vec<u8>) {
+        use std::sync::atomic::Ordering;
+
+        let mut lru = self.lru.lock().unwrap();
+        self.current_bytes.fetch_add(bytes.len(), Ordering::Relaxed);
+        if let Some((_, evicted)) = lru.push(key, bytes) {
+            self.current_bytes
+                .fetch_sub(evicted.len(), Ordering::Relaxed);
+        }
+
+        while self.current_bytes.load(Ordering::Relaxed) > self.max_bytes {
+            let Some((_, evicted)) = lru.pop_lru() else {
+                break;
+            };
+            self.current_bytes
+                .fetch_sub(evicted.len(), Ordering::Relaxed);
+        }
+    }
+}
+
+/// Rejects image paths that could escape the configured source directory, e.g.
+/// via `..` traversal or an absolute path.
+fn is_safe_relative_path(image: &str) -> bool {
+    use std::path::Component;
+    std::path::Path::new(image)
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+}
+
+/// Hashes a cache key into a filesystem-safe filename for the disk tier.
+fn disk_key(key: &str) -> String {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    key.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Returns a counter value unique within this process, used to give each
+/// disk-tier temp file a name that can't collide with a concurrent write.
+fn next_tmp_suffix() -> u64 {
+    static COUNTER: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+    COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+}
+
+/// Keeps the disk tier under `max_bytes` by deleting the least-recently
+/// modified entries first. The disk tier has no in-process ordering of its
+/// own to consult (unlike the in-memory LRU), so this falls back to mtime.
+fn evict_disk_tier(dir: &std::path::Path, max_bytes: usize) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+
+    let mut files: Vec<_> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| !name.contains(".tmp-"))
+        })
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            let modified = metadata.modified().ok()?;
+            Some((entry.path(), metadata.len() as usize, modified))
+        })
+        .collect();
+
+    let mut total_bytes: usize = files.iter().map(|(_, len, _)| *len).sum();
+    if total_bytes <= max_bytes {
+        return;
+    }
+
+    files.sort_by_key(|(_, _, modified)| *modified);
+    for (path, len, _) in files {
+        if total_bytes <= max_bytes {
+            break;
+        }
+        if std::fs::remove_file(&path).is_ok() {
+            total_bytes = total_bytes.saturating_sub(len);
+        }
+    }
+}
+
+/// Decodes `source`, applies `resize`'s resize/crop/encode pipeline, and
+/// returns the resulting bytes. Runs on a `spawn_blocking` worker since
+/// decoding and encoding are CPU-bound.
+fn encode(
+    source: &[u8],
+    image: &str,
+    resize: &Resize,
+    format: Option<OutputFormat>,
+    watermark: Option<&Watermark>,
+) -> Result<Vec<u8>, GetImageError> {
+    let mut im = image::load_from_memory(source).map_err(|_| GetImageError::NotFound)?;
+
+    if resize.width.is_some() || resize.height.is_some() {
+        im = im.resize(
+            resize.width.unwrap_or(u16::MAX) as u32,
+            resize.height.unwrap_or(u16::MAX) as u32,
+            FilterType::Lanczos3,
+        );
+    }
+
+    if resize.cx.is_some() || resize.cy.is_some() || resize.cwidth.is_some() || resize.cheight.is_some()
+    {
+        im = im.crop_imm(
+            resize.cx.unwrap_or(0) as u32,
+            resize.cy.unwrap_or(0) as u32,
+            resize.cwidth.unwrap_or(u16::MAX) as u32,
+            resize.cheight.unwrap_or(u16::MAX) as u32,
+        );
+    }
+
+    if let Some(watermark) = watermark {
+        im = overlay_watermark(im, watermark);
+    }
+
+    let quality = resize.quality.unwrap_or(85);
+    match format {
+        Some(OutputFormat::Webp) => Ok(webp::Encoder::from_image(&im)
+            .map_err(|_| GetImageError::NotFound)?
+            .encode(quality as f32)
+            .to_vec()),
+        Some(OutputFormat::Avif) => encode_avif(&im, quality),
+        Some(OutputFormat::Jxl) => encode_jxl(&im, quality),
+        Some(OutputFormat::Auto) | None => {
+            let mut v = Cursor::new(Vec::new());
+            let format = match image.split('.').last().unwrap_or("jpg") {
+                "jpg" => ImageFormat::Jpeg,
+                "png" => ImageFormat::Png,
+                "gif" => ImageFormat::Gif,
+                _ => ImageFormat::Jpeg,
+            };
+            im.write_to(&mut v, format).map_err(|_| GetImageError::NotFound)?;
+            Ok(v.into_inner())
+        }
+    }
+}
+
+/// Scales `watermark`'s overlay relative to `im`'s dimensions, anchors it at
+/// `watermark.gravity` with `watermark.margin` px of padding, and alpha
+/// blends it onto `im` at `watermark.opacity`.
+fn overlay_watermark(mut im: image::DynamicImage, watermark: &Watermark) -> image::DynamicImage {
+    use image::GenericImageView;
+
+    let (width, height) = im.dimensions();
+    // Scale the overlay to ~20% of the target's width, preserving its aspect ratio.
+    let target_width = (width / 5).max(1);
+    let overlay = watermark
+        .image
+        .resize(target_width, target_width, FilterType::Lanczos3)
+        .to_rgba8();
+
+    let (overlay_width, overlay_height) = overlay.dimensions();
+    let overlay = fade(overlay, watermark.opacity);
+
+    let (x, y) = match watermark.gravity {
+        Gravity::TopLeft => (watermark.margin, watermark.margin),
+        Gravity::TopRight => (
+            width.saturating_sub(overlay_width + watermark.margin),
+            watermark.margin,
+        ),
+        Gravity::BottomLeft => (
+            watermark.margin,
+            height.saturating_sub(overlay_height + watermark.margin),
+        ),
+        Gravity::BottomRight => (
+            width.saturating_sub(overlay_width + watermark.margin),
+            height.saturating_sub(overlay_height + watermark.margin),
+        ),
+        Gravity::Center => (
+            width.saturating_sub(overlay_width) / 2,
+            height.saturating_sub(overlay_height) / 2,
+        ),
+    };
+
+    image::imageops::overlay(&mut im, &overlay, x as i64, y as i64);
+    im
+}
+
+/// Scales an RGBA image's alpha channel by `opacity` (clamped to `0.0..=1.0`).
+fn fade(mut rgba: image::RgbaImage, opacity: f32) -> image::RgbaImage {
+    let opacity = opacity.clamp(0.0, 1.0);
+    if opacity < 1.0 {
+        for pixel in rgba.pixels_mut() {
+            pixel[3] = (pixel[3] as f32 * opacity) as u8;
+        }
+    }
+    rgba
+}
+
+/// Reads a file's bytes asynchronously. On Linux with the `tokio-uring`
+/// feature enabled this goes through `tokio-uring`'s io_uring-backed file
+/// reader instead of the regular threadpool-backed `tokio::fs`, following
+/// the approach `actix-files` took when it adopted io_uring.
+async fn read_file(path: &std::path::Path) -> std::io::Result<Vec<u8>> {
+    #[cfg(all(feature = "tokio-uring", target_os = "linux"))]
+    {
+        read_file_uring(path).await
+    }
+
+    #[cfg(not(all(feature = "tokio-uring", target_os = "linux")))]
+    {
+        tokio::fs::read(path).await
+    }
+}
+
+#[cfg(all(feature = "tokio-uring", target_os = "linux"))]
+async fn read_file_uring(path: &std::path::Path) -> std::io::Result<Vec<u8>> {
+    let path = path.to_owned();
+    let metadata = std::fs::metadata(&path)?;
+
+    let file = tokio_uring::fs::File::open(&path).await?;
+    let buf = Vec::with_capacity(metadata.len() as usize);
+    let (res, buf) = file.read_at(buf, 0).await;
+    res?;
+    file.close().await?;
+
+    Ok(buf)
+}
+
+/// Encodes `im` as AVIF at a fixed encoder speed, taking the quality from the
+/// same `quality` (0-100) knob used for webp.
+fn encode_avif(im: &image::DynamicImage, quality: u8) -> Result<Vec<u8>, GetImageError> {
+    let mut v = Cursor::new(Vec::new());
+    let encoder = image::codecs::avif::AvifEncoder::new_with_speed_quality(
+        &mut v,
+        6, // a reasonable speed/compression tradeoff for on-demand encoding
+        quality,
+    );
+    im.write_with_encoder(encoder).map_err(|_| GetImageError::NotFound)?;
+    Ok(v.into_inner())
+}
+
+/// Encodes `im` as JPEG-XL via `zune-jpegxl`.
+fn encode_jxl(im: &image::DynamicImage, quality: u8) -> Result<Vec<u8>, GetImageError> {
+    let rgb = im.to_rgb8();
+    let options = zune_jpegxl::EncoderOptions::new(
+        rgb.width() as usize,
+        rgb.height() as usize,
+        zune_jpegxl::ColorSpace::RGB,
+        zune_jpegxl::BitDepth::Eight,
+    )
+    .set_quality(quality as f32);
+
+    zune_jpegxl::JxlSimpleEncoder::new(rgb.as_raw(), options)
+        .encode()
+        .map_err(|_| GetImageError::NotFound)
+}
+
+/// Resolves which encoder `get_image` should use, preferring the new `format`
+/// field, falling back to the legacy `webp` boolean for backward
+/// compatibility, and finally negotiating a format from the request's
+/// `Accept` header when nothing was pinned down explicitly (`format=auto` or
+/// no format at all).
+fn resolve_format(resize: &Resize, accept: Option<&str>) -> Option<OutputFormat> {
+    match (resize.format, resize.webp) {
+        (Some(OutputFormat::Auto), _) | (None, None) => negotiate_format(accept),
+        (Some(format), _) => Some(format),
+        (None, Some(true)) => Some(OutputFormat::Webp),
+        (None, Some(false)) => None,
+    }
+}
+
+/// Picks the best format the client advertises via `Accept`, preferring
+/// AVIF, then WebP, and otherwise leaving the original format untouched.
+fn negotiate_format(accept: Option<&str>) -> Option<OutputFormat> {
+    let accepted: Vec<&str> = accept?
+        .split(',')
+        .map(|part| part.split(';').next().unwrap_or("").trim())
+        .collect();
+
+    if accepted.contains(&"image/avif") {
+        Some(OutputFormat::Avif)
+    } else if accepted.contains(&"image/webp") {
+        Some(OutputFormat::Webp)
     } else {
-        let image_type = image.split('.').last().unwrap_or("jpg");
-        format!("image/{image_type}")
+        None
+    }
+}
+
+fn parse_content_type(resize: &Resize, image: &str, accept: Option<&str>) -> String {
+    match resolve_format(resize, accept) {
+        Some(OutputFormat::Webp) => "image/webp".into(),
+        Some(OutputFormat::Avif) => "image/avif".into(),
+        Some(OutputFormat::Jxl) => "image/jxl".into(),
+        Some(OutputFormat::Auto) | None => {
+            let image_type = image.split('.').last().unwrap_or("jpg");
+            format!("image/{image_type}")
+        }
     }
 }
 
@@ -160,31 +774,154 @@ fn test_poem() {
     }
 }
 
-fn key(image: &str, resize: &Resize) -> String {
+#[test]
+fn test_negotiated_format_distinguishes_cache_key() {
+    let resize = Resize {
+        format: Some(OutputFormat::Auto),
+        ..Resize::default()
+    };
+
+    let avif = resolve_format(&resize, Some("image/avif,image/webp"));
+    let webp = resolve_format(&resize, Some("image/webp"));
+    assert_eq!(avif, Some(OutputFormat::Avif));
+    assert_eq!(webp, Some(OutputFormat::Webp));
+
+    let avif_key = key("sample.jpg", &resize, avif);
+    let webp_key = key("sample.jpg", &resize, webp);
+    assert_ne!(avif_key, webp_key);
+}
+
+#[test]
+fn test_store_image_rejects_oversized_upload() {
+    let optimizer = ImageOptimizer::new("./examples/images")
+        .unwrap()
+        .with_upload_config(4, vec![image::ImageFormat::Png]);
+
+    let err = optimizer.store_image(vec![0u8; 16]).unwrap_err();
+    assert!(matches!(err, UploadError::TooLarge));
+}
+
+#[test]
+fn test_store_image_rejects_disallowed_format() {
+    let optimizer = ImageOptimizer::new("./examples/images")
+        .unwrap()
+        .with_upload_config(usize::MAX, vec![image::ImageFormat::Png]);
+
+    let mut jpeg_bytes = Cursor::new(Vec::new());
+    image::DynamicImage::new_rgb8(2, 2)
+        .write_to(&mut jpeg_bytes, ImageFormat::Jpeg)
+        .unwrap();
+
+    let err = optimizer
+        .store_image(jpeg_bytes.into_inner())
+        .unwrap_err();
+    assert!(matches!(err, UploadError::UnsupportedFormat));
+}
+
+fn key(image: &str, resize: &Resize, format: Option<OutputFormat>) -> String {
     let mut key: String = resize.to_string();
+    // `resize.to_string()` only reflects an explicitly requested, non-negotiated
+    // format; fold in the negotiated one too (mirroring `resolve_format`'s own
+    // notion of "unpinned"), so distinct `Accept` headers don't collide.
+    let negotiated = matches!(
+        (resize.format, resize.webp),
+        (Some(OutputFormat::Auto), _) | (None, None)
+    );
+    if negotiated {
+        if let Some(format) = format {
+            key.push_str(&format!("f{:?}", format));
+        }
+    }
     key.push_str(image);
     key
 }
 
 #[derive(thiserror::Error, Debug)]
-#[error("Image not found")]
-struct ImageNotFound;
+pub enum GetImageError {
+    #[error("image not found")]
+    NotFound,
+    #[error("failed to fetch image from origin")]
+    Upstream,
+}
 
-impl From<std::io::Error> for ImageNotFound {
+impl From<std::io::Error> for GetImageError {
     fn from(_: std::io::Error) -> Self {
-        Self
+        Self::NotFound
     }
 }
 
 #[cfg(feature = "axum")]
-impl axum::response::IntoResponse for ImageNotFound {
+impl axum::response::IntoResponse for GetImageError {
     fn into_response(self) -> axum::response::Response {
-        axum::http::StatusCode::NOT_FOUND.into_response()
+        let status = match self {
+            GetImageError::NotFound => axum::http::StatusCode::NOT_FOUND,
+            GetImageError::Upstream => axum::http::StatusCode::BAD_GATEWAY,
+        };
+        status.into_response()
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum UploadError {
+    #[error("upload exceeds the maximum allowed size")]
+    TooLarge,
+    #[error("upload is not one of the allowed image formats")]
+    UnsupportedFormat,
+    #[error("upload does not decode as a valid image")]
+    InvalidImage,
+    #[error("failed to store upload")]
+    Io,
+}
+
+impl UploadError {
+    fn status_code(&self) -> http::StatusCode {
+        match self {
+            UploadError::TooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
+            UploadError::UnsupportedFormat | UploadError::InvalidImage => {
+                http::StatusCode::BAD_REQUEST
+            }
+            UploadError::Io => http::StatusCode::INTERNAL_SERVER_ERROR,
+        }
     }
 }
 
+#[cfg(feature = "axum")]
+impl axum::response::IntoResponse for UploadError {
+    fn into_response(self) -> axum::response::Response {
+        (self.status_code(), self.to_string()).into_response()
+    }
+}
+
+/// Lowercase hex SHA-256 of `bytes`, used as the content-addressed filename
+/// for uploads (see [`ImageOptimizer::store_image`]).
+fn sha256_hex(bytes: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+    use std::fmt::Write;
+
+    let digest = Sha256::digest(bytes);
+    let mut hex = String::with_capacity(digest.len() * 2);
+    for byte in digest {
+        write!(hex, "{byte:02x}").unwrap();
+    }
+    hex
+}
+
+/// The output encoder to re-encode an image with.
 #[derive(Deserialize, Debug, Clone, Copy, PartialEq, PartialOrd, Hash, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Webp,
+    Avif,
+    Jxl,
+    /// Let the server pick the best format from the request's `Accept` header:
+    /// AVIF, then WebP, otherwise the original format.
+    Auto,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, PartialOrd, Hash, Eq)]
 pub struct Resize {
+    pub format: Option<OutputFormat>,
+    /// Deprecated, use `format=webp` instead. Kept around for backward compatibility.
     pub webp: Option<bool>,
     pub quality: Option<u8>,
     pub width: Option<u16>,
@@ -193,13 +930,18 @@ pub struct Resize {
     pub cy: Option<u16>,
     pub cwidth: Option<u16>,
     pub cheight: Option<u16>,
+    /// Overrides the server-configured watermark (see
+    /// [`ImageOptimizer::with_watermark`]) for this request; `?watermark=false` disables it.
+    pub watermark: Option<bool>,
 }
 
 impl Resize {
     pub fn to_string(&self) -> String {
         let mut s = String::new();
-        if let Some(_) = self.webp {
-            s.push_str("webp");
+        if let Some(format) = self.format {
+            s.push_str(&format!("f{:?}", format));
+        } else if let Some(webp) = self.webp {
+            s.push_str(&format!("webp{webp}"));
         }
         if let Some(quality) = self.quality {
             s.push_str(&format!("q{}", quality));
@@ -222,6 +964,9 @@ impl Resize {
         if let Some(cheight) = self.cheight {
             s.push_str(&format!("ch{}", cheight));
         }
+        if let Some(watermark) = self.watermark {
+            s.push_str(&format!("wm{}", watermark));
+        }
         s
     }
 }